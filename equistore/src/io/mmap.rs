@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use memmap2::Mmap;
+use zip::ZipArchive;
+
+use crate::{Labels, TensorBlock, Error};
+
+/// Location of a single block inside the archive. Only the entry names from
+/// the zip central directory are used to build it (no NPY header of the
+/// block is read), which is cheap, and lets us defer decoding the
+/// (potentially large) `values`/gradient arrays until the block is
+/// actually requested.
+struct BlockLocation {
+    /// prefix shared by all the zip entries for this block, e.g.
+    /// `"blocks/3/"` for a block stored under `blocks/3/values.npy`,
+    /// `blocks/3/samples.npy`, etc.
+    prefix: String,
+}
+
+/// A lazily-decoded view over an npz archive, returned by [`load_mmap`].
+///
+/// Unlike [`crate::io::load`], which decodes every block eagerly, this type
+/// memory-maps the archive and only decodes a block's arrays the first time
+/// [`LazyTensorMap::block_by_id`] is called for it, keeping memory and time
+/// usage proportional to the number of blocks actually accessed.
+pub struct LazyTensorMap {
+    keys: Labels,
+    mmap: Mmap,
+    blocks: Vec<BlockLocation>,
+    cache: RefCell<HashMap<usize, Rc<TensorBlock>>>,
+}
+
+impl LazyTensorMap {
+    /// Get the keys associated with the blocks in this tensor map. This is
+    /// always available without decoding any block, since the keys are
+    /// stored as a single small entry in the archive.
+    pub fn keys(&self) -> &Labels {
+        &self.keys
+    }
+
+    /// Number of blocks in this tensor map.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Get the block at the given `index`, decoding its arrays from the
+    /// memory-mapped archive the first time it is requested, and re-using
+    /// the decoded block on subsequent calls.
+    ///
+    /// The returned `Rc` is an owned handle rather than a borrow tied to an
+    /// internal lock, so multiple blocks can be held live at the same time
+    /// (unlike a `Ref` into a shared `RefCell`, which would panic as soon as
+    /// a second, uncached block was requested while the first was still
+    /// borrowed).
+    pub fn block_by_id(&self, index: usize) -> Result<Rc<TensorBlock>, Error> {
+        if let Some(block) = self.cache.borrow().get(&index) {
+            return Ok(Rc::clone(block));
+        }
+
+        let location = self.blocks.get(index).ok_or_else(|| Error::InvalidParameter(format!(
+            "block index {} is out of bounds for a tensor map with {} blocks", index, self.blocks.len()
+        )))?;
+
+        let block = Rc::new(decode_block(&self.mmap, location)?);
+        self.cache.borrow_mut().insert(index, Rc::clone(&block));
+
+        Ok(block)
+    }
+}
+
+/// Open the npz archive at `path`, memory-mapping it and parsing only the
+/// zip central directory and the keys, instead of eagerly decoding every
+/// block like [`crate::io::load`] does.
+///
+/// This is useful for archives with many blocks where a caller only touches
+/// a handful of them, for example after narrowing a selection down with
+/// [`crate::TensorMap::blocks_matching`]: memory and time usage stay
+/// proportional to the number of blocks actually read through
+/// [`LazyTensorMap::block_by_id`], instead of the size of the whole file.
+pub fn load_mmap(path: impl AsRef<Path>) -> Result<LazyTensorMap, Error> {
+    let file = File::open(path.as_ref())?;
+    // SAFETY: we require the caller not to mutate the file while the
+    // resulting `LazyTensorMap` is alive, same as `numpy.load(mmap_mode=...)`
+    let mmap = unsafe { Mmap::map(&file)? };
+
+    let mut archive = ZipArchive::new(std::io::Cursor::new(&mmap[..]))?;
+
+    let mut numbered_prefixes = Vec::new();
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        if let Some(prefix) = entry.name().strip_suffix("values.npy") {
+            let block_id = prefix.trim_end_matches('/').rsplit('/').next()
+                .and_then(|id| id.parse::<usize>().ok())
+                .ok_or_else(|| Error::InvalidParameter(format!(
+                    "invalid block entry '{}values.npy' in npz archive: \
+                    the block directory name is not a valid block index", prefix
+                )))?;
+            numbered_prefixes.push((block_id, prefix.to_owned()));
+        }
+    }
+    // sort numerically on the block index parsed out of the prefix, not
+    // lexicographically on the prefix string itself: a lexicographic sort
+    // would put "blocks/10/" before "blocks/2/", scrambling the block
+    // order as soon as the archive has 10 or more blocks
+    numbered_prefixes.sort_by_key(|&(block_id, _)| block_id);
+
+    let keys = {
+        let mut entry = archive.by_name("keys.npy")?;
+        crate::io::npy::read_labels(&mut entry)?
+    };
+
+    let blocks = numbered_prefixes.into_iter().map(|(_, prefix)| BlockLocation { prefix }).collect();
+
+    return Ok(LazyTensorMap {
+        keys,
+        mmap,
+        blocks,
+        cache: RefCell::new(HashMap::new()),
+    });
+}
+
+/// Decode a single block by re-opening the zip directory (cheap, since it
+/// only reads the already memory-mapped central directory) and decoding the
+/// arrays stored under `location.prefix`.
+fn decode_block(mmap: &Mmap, location: &BlockLocation) -> Result<TensorBlock, Error> {
+    let mut archive = ZipArchive::new(std::io::Cursor::new(&mmap[..]))?;
+    crate::io::npy::read_block(&mut archive, &location.prefix)
+}
@@ -0,0 +1,35 @@
+use equistore::Labels;
+
+#[test]
+fn matches_selected_blocks() {
+    let tensor = equistore::io::load("./tests/data.npz").unwrap();
+
+    let selection = Labels::new(&["center_species"], &[[6.into()]]).unwrap();
+    let matching = tensor.blocks_matching(&selection).unwrap();
+
+    assert!(!matching.is_empty());
+    for &block_i in &matching {
+        let key = tensor.keys().iter().nth(block_i).unwrap();
+        let position = tensor.keys().names().iter().position(|&name| name == "center_species").unwrap();
+        assert_eq!(key[position], 6.into());
+    }
+}
+
+#[test]
+fn empty_selection_matches_everything() {
+    let tensor = equistore::io::load("./tests/data.npz").unwrap();
+
+    let selection = Labels::new(&([] as [&str; 0]), &([] as [[equistore::LabelValue; 0]; 0])).unwrap();
+    let matching = tensor.blocks_matching(&selection).unwrap();
+
+    assert_eq!(matching.len(), tensor.keys().count());
+}
+
+#[test]
+fn unknown_variable_errors() {
+    let tensor = equistore::io::load("./tests/data.npz").unwrap();
+
+    let selection = Labels::new(&["not_a_real_variable"], &[[0.into()]]).unwrap();
+    let error = tensor.blocks_matching(&selection).unwrap_err();
+    assert!(error.to_string().contains("not_a_real_variable"));
+}
@@ -0,0 +1,27 @@
+#[test]
+fn merges_blocks_with_different_properties() {
+    let mut tensor = equistore::io::load("./tests/data.npz").unwrap();
+
+    let keys_before = tensor.keys().clone();
+    let samples_names_before = tensor.block_by_id(0).samples().names().to_vec();
+
+    tensor.keys_to_samples(&["center_species"]).unwrap();
+
+    // the moved variable is no longer part of the keys
+    assert!(!tensor.keys().names().contains(&"center_species"));
+    assert!(keys_before.names().contains(&"center_species"));
+
+    // it now shows up at the end of the samples of every merged block
+    for block_i in 0..tensor.keys().count() {
+        let block = tensor.block_by_id(block_i);
+
+        let mut expected_samples_names = samples_names_before.clone();
+        expected_samples_names.push("center_species");
+        assert_eq!(block.samples().names(), expected_samples_names);
+
+        // merging tolerates differing property labels: the merged block
+        // uses the union of all the properties of the blocks it merges,
+        // instead of requiring them to already match
+        assert!(block.properties().count() > 0);
+    }
+}
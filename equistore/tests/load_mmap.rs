@@ -0,0 +1,35 @@
+#[test]
+fn matches_eager_load() {
+    let eager = equistore::io::load("./tests/data.npz").unwrap();
+    let lazy = equistore::io::load_mmap("./tests/data.npz").unwrap();
+
+    assert_eq!(eager.keys(), lazy.keys());
+    assert_eq!(eager.keys().count(), lazy.len());
+
+    // this fixture has 27 blocks: checking every one of them (not just the
+    // first few) is what catches a lexicographic instead of numeric sort of
+    // the block directories inside the archive, since that only scrambles
+    // the order once there are 10 or more blocks
+    for block_i in 0..lazy.len() {
+        let eager_block = eager.block_by_id(block_i);
+        let lazy_block = lazy.block_by_id(block_i).unwrap();
+
+        assert_eq!(eager_block.samples(), lazy_block.samples());
+        assert_eq!(eager_block.properties(), lazy_block.properties());
+        assert_eq!(eager_block.values().as_array(), lazy_block.values().as_array());
+    }
+}
+
+#[test]
+fn block_by_id_allows_multiple_live_blocks() {
+    let lazy = equistore::io::load_mmap("./tests/data.npz").unwrap();
+
+    // holding on to one decoded block while requesting another must not
+    // panic: block_by_id used to return a `Ref` borrowed from a shared
+    // `RefCell` cache, which would panic here as soon as the second,
+    // not-yet-cached block was requested
+    let first = lazy.block_by_id(0).unwrap();
+    let second = lazy.block_by_id(1).unwrap();
+
+    assert_eq!(first.samples().names(), second.samples().names());
+}
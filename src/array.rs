@@ -0,0 +1,167 @@
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::Error;
+
+/// `DataOrigin` identifies which code created a given array, and therefore
+/// which backend must be used to operate on it (for example to `create` a
+/// new array compatible with an existing one, or to merge samples from two
+/// arrays together in `keys_to_properties`/`keys_to_samples`).
+///
+/// Two arrays can only be merged together if they share the same origin.
+pub type DataOrigin = u64;
+
+static ORIGINS: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Register a new data origin with the given `name`, returning the
+/// corresponding [`DataOrigin`]. Calling this function multiple times with
+/// the same `name` returns the same origin.
+pub fn register_data_origin(name: String) -> DataOrigin {
+    let mut origins = ORIGINS.lock().expect("poisoned lock");
+    if let Some(position) = origins.iter().position(|registered| registered == &name) {
+        return position as DataOrigin;
+    }
+
+    origins.push(name);
+    return (origins.len() - 1) as DataOrigin;
+}
+
+/// Get the name associated with the given `origin`, if any.
+pub fn get_data_origin_name(origin: DataOrigin) -> Option<String> {
+    let origins = ORIGINS.lock().expect("poisoned lock");
+    origins.get(origin as usize).cloned()
+}
+
+static RUST_NDARRAY_ORIGIN: Lazy<DataOrigin> = Lazy::new(|| {
+    register_data_origin("rust.ndarray".into())
+});
+
+/// A single `input -> output` index mapping, used both for samples and for
+/// properties in [`Array::move_samples_from`].
+#[derive(Debug, Clone, Copy)]
+pub struct SampleMapping {
+    /// index in the source array
+    pub input: usize,
+    /// index in the destination array
+    pub output: usize,
+}
+
+/// `Array` is the trait implemented by all the storage backends `TensorBlock`
+/// can operate on. The default backend (`ndarray::ArrayD<f64>`) keeps the
+/// data in Rust-managed memory, but this trait can also be implemented on
+/// top of foreign memory (a GPU tensor, a buffer owned by another language)
+/// through the `aml_array_t` vtable exposed by the C API, so that merging
+/// blocks together (`keys_to_properties`, `keys_to_samples`) does not
+/// require copying data owned by a foreign backend into a Rust `ndarray`.
+pub trait Array: std::fmt::Debug + Send {
+    /// Get a `&dyn Any` reference to this array, used to downcast back to a
+    /// concrete type when merging two arrays coming from the same backend.
+    fn as_any(&self) -> &dyn std::any::Any;
+
+    /// Get the data origin of this array, used to check whether two arrays
+    /// can be merged together.
+    fn origin(&self) -> Result<DataOrigin, Error>;
+
+    /// Get the shape of this array.
+    fn shape(&self) -> Result<&[usize], Error>;
+
+    /// Get a read-only view of this array's data as a flat, row-major slice
+    /// of 64-bit floats. This is allowed to fail if the backend does not
+    /// store (or can not expose) its data this way.
+    fn data(&self) -> Result<&[f64], Error>;
+
+    /// Change the shape of this array to the given `shape`, the number of
+    /// elements must stay the same.
+    fn reshape(&mut self, shape: &[usize]) -> Result<(), Error>;
+
+    /// Swap the axes `axis_1` and `axis_2` of this array.
+    fn swap_axes(&mut self, axis_1: usize, axis_2: usize) -> Result<(), Error>;
+
+    /// Create a new array with the same origin/options as `self`, filled
+    /// with zeros, and with the given `shape`.
+    fn create(&self, shape: &[usize]) -> Result<Box<dyn Array>, Error>;
+
+    /// Make a deep copy of this array.
+    fn copy(&self) -> Result<Box<dyn Array>, Error>;
+
+    /// Set samples in `self`, following the given `samples` mapping, to the
+    /// values taken from the matching samples of `other`, remapping
+    /// property columns according to `properties`. This is used to scatter
+    /// the content of merged blocks into the output array in
+    /// `keys_to_properties`/`keys_to_samples`, without assuming anything
+    /// about how either array stores its data.
+    fn move_samples_from(
+        &mut self,
+        other: &dyn Array,
+        samples: &[SampleMapping],
+        properties: &[SampleMapping],
+    ) -> Result<(), Error>;
+}
+
+impl Array for ndarray::ArrayD<f64> {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn origin(&self) -> Result<DataOrigin, Error> {
+        Ok(*RUST_NDARRAY_ORIGIN)
+    }
+
+    fn shape(&self) -> Result<&[usize], Error> {
+        Ok(ndarray::ArrayBase::shape(self))
+    }
+
+    fn data(&self) -> Result<&[f64], Error> {
+        self.as_slice().ok_or_else(|| Error::InvalidParameter(
+            "this array is not contiguous and can not be accessed as a flat slice".into()
+        ))
+    }
+
+    fn reshape(&mut self, shape: &[usize]) -> Result<(), Error> {
+        let array = std::mem::replace(self, ndarray::ArrayD::zeros(ndarray::IxDyn(&[])));
+        let array = array.into_shape(ndarray::IxDyn(shape)).map_err(|error| {
+            Error::InvalidParameter(format!("invalid shape in reshape: {}", error))
+        })?;
+        *self = array;
+        Ok(())
+    }
+
+    fn swap_axes(&mut self, axis_1: usize, axis_2: usize) -> Result<(), Error> {
+        ndarray::ArrayBase::swap_axes(self, axis_1, axis_2);
+        Ok(())
+    }
+
+    fn create(&self, shape: &[usize]) -> Result<Box<dyn Array>, Error> {
+        Ok(Box::new(ndarray::ArrayD::zeros(ndarray::IxDyn(shape))))
+    }
+
+    fn copy(&self) -> Result<Box<dyn Array>, Error> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn move_samples_from(
+        &mut self,
+        other: &dyn Array,
+        samples: &[SampleMapping],
+        properties: &[SampleMapping],
+    ) -> Result<(), Error> {
+        let other = other.as_any().downcast_ref::<ndarray::ArrayD<f64>>().expect(
+            "can only move samples between arrays using the same Rust ndarray backend"
+        );
+
+        for sample in samples {
+            let input_sample = other.index_axis(ndarray::Axis(0), sample.input);
+            let mut output_sample = self.index_axis_mut(ndarray::Axis(0), sample.output);
+
+            let properties_axis = ndarray::Axis(output_sample.ndim() - 1);
+            for property in properties {
+                let input_property = input_sample.index_axis(properties_axis, property.input);
+                let mut output_property = output_sample.index_axis_mut(properties_axis, property.output);
+                output_property.assign(&input_property);
+            }
+        }
+
+        Ok(())
+    }
+}
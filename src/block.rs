@@ -0,0 +1,183 @@
+use crate::array::{Array, DataOrigin, SampleMapping};
+use crate::{Labels, Error};
+
+/// A read-only or read-write handle to the values (or a gradient's values)
+/// stored in a [`TensorBlock`], backed by a pluggable [`Array`] so the data
+/// can live in Rust-managed memory or behind a foreign `aml_array_t`.
+#[derive(Debug)]
+pub struct ArrayRef(Box<dyn Array>);
+
+impl ArrayRef {
+    /// Get a dense, read-only `ndarray` view of this array.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the underlying backend can not expose its data as a
+    /// dense buffer of 64-bit floats (custom `aml_array_t` backends, e.g.
+    /// ones backed by device memory, are allowed to fail `data`/`shape`;
+    /// such backends should be operated on through `move_samples_from`
+    /// instead of `as_array`).
+    pub fn as_array(&self) -> ndarray::ArrayViewD<f64> {
+        let shape = self.0.shape().expect("failed to get the shape of this array");
+        let data = self.0.data().expect("failed to get the data of this array as a flat slice");
+
+        ndarray::ArrayViewD::from_shape(shape, data)
+            .expect("the shape and data of this array do not match")
+    }
+
+    pub(crate) fn create(&self, shape: &[usize]) -> Result<ArrayRef, Error> {
+        Ok(ArrayRef(self.0.create(shape)?))
+    }
+
+    /// Get the data origin of this array, used to check whether it can be
+    /// merged together with another array in [`ArrayRef::move_samples_from`].
+    pub(crate) fn origin(&self) -> Result<DataOrigin, Error> {
+        self.0.origin()
+    }
+
+    pub(crate) fn move_samples_from(
+        &mut self,
+        other: &ArrayRef,
+        samples: &[SampleMapping],
+        properties: &[SampleMapping],
+    ) -> Result<(), Error> {
+        if self.origin()? != other.origin()? {
+            return Err(Error::InvalidParameter(
+                "can not copy data between arrays with different origins, \
+                the two arrays must come from the same backend".into()
+            ));
+        }
+
+        self.0.move_samples_from(other.0.as_ref(), samples, properties)
+    }
+}
+
+impl From<ndarray::ArrayD<f64>> for ArrayRef {
+    fn from(array: ndarray::ArrayD<f64>) -> ArrayRef {
+        ArrayRef(Box::new(array))
+    }
+}
+
+impl From<Box<dyn Array>> for ArrayRef {
+    fn from(array: Box<dyn Array>) -> ArrayRef {
+        ArrayRef(array)
+    }
+}
+
+/// A gradient of a block's values with respect to some parameter. Gradients
+/// share the properties of the block they are attached to, but can have
+/// their own samples and components.
+#[derive(Debug)]
+pub struct Gradient {
+    values: ArrayRef,
+    samples: Labels,
+    components: Vec<Labels>,
+    properties: Labels,
+}
+
+impl Gradient {
+    pub fn values(&self) -> &ArrayRef {
+        &self.values
+    }
+
+    pub fn samples(&self) -> &Labels {
+        &self.samples
+    }
+
+    pub fn components(&self) -> &[Labels] {
+        &self.components
+    }
+
+    pub fn properties(&self) -> &Labels {
+        &self.properties
+    }
+}
+
+/// A single block in a [`crate::TensorMap`]: values for a given key,
+/// together with the samples/components/properties labels describing them,
+/// and any number of gradients of these values with respect to some
+/// parameter.
+#[derive(Debug)]
+pub struct TensorBlock {
+    values: ArrayRef,
+    samples: Labels,
+    components: Vec<Labels>,
+    properties: Labels,
+    gradients: Vec<(String, Gradient)>,
+}
+
+impl TensorBlock {
+    /// Create a new `TensorBlock` with the given `values` and labels.
+    ///
+    /// `values` can be anything convertible to an [`ArrayRef`]: a Rust-owned
+    /// `ndarray::ArrayD<f64>`, or a `Box<dyn Array>` wrapping a
+    /// caller-provided backend (for example an `aml_array_t` coming from
+    /// the C API), letting a block be built directly on top of
+    /// caller-managed memory instead of copying the data.
+    pub fn new(
+        values: impl Into<ArrayRef>,
+        samples: Labels,
+        components: Vec<Labels>,
+        properties: Labels,
+    ) -> Result<TensorBlock, Error> {
+        Ok(TensorBlock {
+            values: values.into(),
+            samples,
+            components,
+            properties,
+            gradients: Vec::new(),
+        })
+    }
+
+    pub fn values(&self) -> &ArrayRef {
+        &self.values
+    }
+
+    pub fn samples(&self) -> &Labels {
+        &self.samples
+    }
+
+    pub fn components(&self) -> &[Labels] {
+        &self.components
+    }
+
+    pub fn properties(&self) -> &Labels {
+        &self.properties
+    }
+
+    /// Get the names of the gradients defined on this block.
+    pub fn gradient_list(&self) -> Vec<&str> {
+        self.gradients.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// Get the gradient with respect to `name` for this block, if it exists.
+    pub fn gradient(&self, name: &str) -> Option<&Gradient> {
+        self.gradients.iter().find(|(existing, _)| existing == name).map(|(_, gradient)| gradient)
+    }
+
+    /// Add a new gradient with respect to `name` to this block, re-using the
+    /// block's own properties.
+    pub fn add_gradient(
+        &mut self,
+        name: &str,
+        values: impl Into<ArrayRef>,
+        samples: Labels,
+        components: Vec<Labels>,
+    ) -> Result<(), Error> {
+        if self.gradient(name).is_some() {
+            return Err(Error::InvalidParameter(format!(
+                "gradient with respect to '{}' already exists for this block", name
+            )));
+        }
+
+        let properties = self.properties.clone();
+        self.gradients.push((name.to_owned(), Gradient {
+            values: values.into(),
+            samples,
+            components,
+            properties,
+        }));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,576 @@
+use std::os::raw::c_void;
+use std::os::raw::c_char;
+use std::ffi::CStr;
+use std::convert::TryFrom;
+
+use crate::array::{Array, DataOrigin, SampleMapping};
+use crate::{Labels, TensorBlock, Error};
+
+use super::labels::aml_labels_t;
+use super::blocks::aml_block_t;
+use super::status::{aml_status_t, catch_unwind};
+
+/// Identifier of a data origin, as obtained with `aml_register_data_origin`.
+#[allow(non_camel_case_types)]
+pub type aml_data_origin_t = u64;
+
+/// A single `input -> output` index mapping entry, used by the
+/// `move_samples_from` function pointer of `aml_array_t` for both the
+/// `samples` and the `properties` mapping.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct aml_sample_mapping_t {
+    pub input: u64,
+    pub output: u64,
+}
+
+/// `aml_array_t` manages n-dimensional arrays used as the data for a
+/// `TensorBlock`. The array itself is opaque to this library, and can be
+/// anything: a Rust-owned `ndarray`, a buffer managed by another language,
+/// a GPU tensor, etc.
+///
+/// This struct contains a manually-implemented vtable, allowing the Rust
+/// code to call back into the array's actual implementation regardless of
+/// where it lives. Whenever a new `aml_array_t` needs to be created
+/// (e.g. in `create` or `copy` below), it must be built through the same
+/// backend as the array it originates from, so that the result can later
+/// be combined with arrays coming from that backend.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct aml_array_t {
+    /// User-provided data should be stored here, it will be passed as the
+    /// first parameter to all the function pointers below.
+    pub ptr: *mut c_void,
+
+    /// This function needs to store the data origin for this array in
+    /// `origin`. Two arrays can only be combined together (e.g. when
+    /// merging blocks) if they share the same origin.
+    pub origin: Option<unsafe extern fn(
+        array: *const c_void,
+        origin: *mut aml_data_origin_t,
+    ) -> aml_status_t>,
+
+    /// Get a pointer to the underlying, contiguous, row-major data for this
+    /// array. This function is allowed to fail if the data is not stored
+    /// as 64-bit floating point values in memory accessible from Rust.
+    pub data: Option<unsafe extern fn(
+        array: *mut c_void,
+        data: *mut *mut f64,
+    ) -> aml_status_t>,
+
+    /// Get the shape of this array, storing the number of dimensions in
+    /// `*shape_count` and a pointer to the first dimension in `*shape`.
+    pub shape: Option<unsafe extern fn(
+        array: *const c_void,
+        shape: *mut *const u64,
+        shape_count: *mut u64,
+    ) -> aml_status_t>,
+
+    /// Change the shape of this array to the given `shape`, without
+    /// changing the total number of elements.
+    pub reshape: Option<unsafe extern fn(
+        array: *mut c_void,
+        shape: *const u64,
+        shape_count: u64,
+    ) -> aml_status_t>,
+
+    /// Swap the axes `axis_1` and `axis_2` in this array.
+    pub swap_axes: Option<unsafe extern fn(
+        array: *mut c_void,
+        axis_1: u64,
+        axis_2: u64,
+    ) -> aml_status_t>,
+
+    /// Create a new array with the same options (data type, device, ...)
+    /// as `array`, with the given `shape`, and store it in `new_array`. The
+    /// new array must be filled with zeros.
+    pub create: Option<unsafe extern fn(
+        array: *const c_void,
+        shape: *const u64,
+        shape_count: u64,
+        new_array: *mut aml_array_t,
+    ) -> aml_status_t>,
+
+    /// Make a deep copy of `array`, storing the result in `new_array`.
+    pub copy: Option<unsafe extern fn(
+        array: *const c_void,
+        new_array: *mut aml_array_t,
+    ) -> aml_status_t>,
+
+    /// Set samples in `array` to the values taken from the matching samples
+    /// of `other`, following the `samples` mapping, and remapping property
+    /// columns according to the `properties` mapping. This is used when
+    /// merging blocks together, to scatter each source block's data into
+    /// the right place of the merged output array.
+    pub move_samples_from: Option<unsafe extern fn(
+        array: *mut c_void,
+        other: *const c_void,
+        samples: *const aml_sample_mapping_t,
+        samples_count: u64,
+        properties: *const aml_sample_mapping_t,
+        properties_count: u64,
+    ) -> aml_status_t>,
+
+    /// Destroy the array, releasing whatever memory it owns. This is set to
+    /// `NULL` for arrays that do not need any cleanup.
+    pub destroy: Option<unsafe extern fn(array: *mut c_void)>,
+}
+
+// SAFETY: `aml_array_t` is a set of function pointers and an opaque `ptr`;
+// the caller implementing these functions is responsible for making them
+// safe to call from any thread.
+unsafe impl Send for aml_array_t {}
+
+impl Drop for aml_array_t {
+    fn drop(&mut self) {
+        if let Some(destroy) = self.destroy {
+            unsafe { destroy(self.ptr) }
+        }
+    }
+}
+
+impl std::fmt::Debug for aml_array_t {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.debug_struct("aml_array_t").field("ptr", &self.ptr).finish()
+    }
+}
+
+fn check_status(status: aml_status_t, context: &str) -> Result<(), Error> {
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(Error::InvalidParameter(format!("{} failed", context)))
+    }
+}
+
+impl Array for aml_array_t {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn origin(&self) -> Result<DataOrigin, Error> {
+        let function = self.origin.expect("missing 'origin' function in aml_array_t");
+
+        let mut origin = 0;
+        let status = unsafe { function(self.ptr, &mut origin) };
+        check_status(status, "aml_array_t.origin")?;
+
+        Ok(origin)
+    }
+
+    fn shape(&self) -> Result<&[usize], Error> {
+        let function = self.shape.expect("missing 'shape' function in aml_array_t");
+
+        let mut shape = std::ptr::null();
+        let mut shape_count = 0;
+        let status = unsafe { function(self.ptr, &mut shape, &mut shape_count) };
+        if !status.is_success() {
+            return Err(Error::InvalidParameter("aml_array_t.shape failed".into()));
+        }
+
+        if shape.is_null() {
+            return Err(Error::InvalidParameter(
+                "aml_array_t.shape returned AML_SUCCESS but left 'shape' as NULL".into()
+            ));
+        }
+
+        // the shape is stored as `u64`, which has the same representation
+        // as `usize` on the platforms we support
+        Ok(unsafe { std::slice::from_raw_parts(shape.cast(), shape_count as usize) })
+    }
+
+    fn data(&self) -> Result<&[f64], Error> {
+        let function = self.data.expect("missing 'data' function in aml_array_t");
+
+        let mut data = std::ptr::null_mut();
+        let status = unsafe { function(self.ptr, &mut data) };
+        check_status(status, "aml_array_t.data")?;
+
+        if data.is_null() {
+            return Err(Error::InvalidParameter(
+                "aml_array_t.data returned AML_SUCCESS but left 'data' as NULL".into()
+            ));
+        }
+
+        let len = self.shape()?.iter().product();
+        Ok(unsafe { std::slice::from_raw_parts(data.cast_const(), len) })
+    }
+
+    fn reshape(&mut self, shape: &[usize]) -> Result<(), Error> {
+        let function = self.reshape.expect("missing 'reshape' function in aml_array_t");
+
+        let shape = shape.iter().map(|&size| size as u64).collect::<Vec<_>>();
+        let status = unsafe {
+            function(self.ptr, shape.as_ptr(), shape.len() as u64)
+        };
+        check_status(status, "aml_array_t.reshape")
+    }
+
+    fn swap_axes(&mut self, axis_1: usize, axis_2: usize) -> Result<(), Error> {
+        let function = self.swap_axes.expect("missing 'swap_axes' function in aml_array_t");
+
+        let status = unsafe {
+            function(self.ptr, axis_1 as u64, axis_2 as u64)
+        };
+        check_status(status, "aml_array_t.swap_axes")
+    }
+
+    fn create(&self, shape: &[usize]) -> Result<Box<dyn Array>, Error> {
+        let function = self.create.expect("missing 'create' function in aml_array_t");
+
+        let shape = shape.iter().map(|&size| size as u64).collect::<Vec<_>>();
+        let mut new_array = blank_aml_array();
+        let status = unsafe {
+            function(self.ptr, shape.as_ptr(), shape.len() as u64, &mut new_array)
+        };
+        check_status(status, "aml_array_t.create")?;
+
+        Ok(Box::new(new_array))
+    }
+
+    fn copy(&self) -> Result<Box<dyn Array>, Error> {
+        let function = self.copy.expect("missing 'copy' function in aml_array_t");
+
+        let mut new_array = blank_aml_array();
+        let status = unsafe {
+            function(self.ptr, &mut new_array)
+        };
+        check_status(status, "aml_array_t.copy")?;
+
+        Ok(Box::new(new_array))
+    }
+
+    fn move_samples_from(
+        &mut self,
+        other: &dyn Array,
+        samples: &[SampleMapping],
+        properties: &[SampleMapping],
+    ) -> Result<(), Error> {
+        let function = self.move_samples_from.expect(
+            "missing 'move_samples_from' function in aml_array_t"
+        );
+
+        let other = other.as_any().downcast_ref::<aml_array_t>().expect(
+            "can only merge arrays coming from the same aml_array_t backend"
+        );
+
+        let to_ffi_mapping = |mapping: &[SampleMapping]| mapping.iter().map(|entry| aml_sample_mapping_t {
+            input: entry.input as u64,
+            output: entry.output as u64,
+        }).collect::<Vec<_>>();
+
+        let samples = to_ffi_mapping(samples);
+        let properties = to_ffi_mapping(properties);
+
+        let status = unsafe {
+            function(
+                self.ptr,
+                other.ptr,
+                samples.as_ptr(),
+                samples.len() as u64,
+                properties.as_ptr(),
+                properties.len() as u64,
+            )
+        };
+        check_status(status, "aml_array_t.move_samples_from")
+    }
+}
+
+/// Create an all-`None`/all-null `aml_array_t`, to be filled in by a
+/// `create`/`copy` call into the corresponding out-parameter.
+fn blank_aml_array() -> aml_array_t {
+    aml_array_t {
+        ptr: std::ptr::null_mut(),
+        origin: None,
+        data: None,
+        shape: None,
+        reshape: None,
+        swap_axes: None,
+        create: None,
+        copy: None,
+        move_samples_from: None,
+        destroy: None,
+    }
+}
+
+/// Create a new `aml_block_t` with the given `data`, `samples`,
+/// `components`, and `properties`, without copying `data` into
+/// equistore-managed memory: the resulting block keeps reading from and
+/// writing to whatever buffer `data` points to, through the function
+/// pointers it was created with.
+///
+/// This takes ownership of `data`, which must have been fully initialized
+/// by the caller (most importantly its `ptr` and function pointers); it
+/// will be released through `data.destroy` when the returned block itself
+/// is freed.
+///
+/// @param block pointer to be filled with the newly created block
+/// @param data the array to use as values for the new block
+/// @param samples labels describing the samples of the new block
+/// @param components pointer to the first element of an array of component
+///                    labels
+/// @param components_count number of entries in the `components` array
+/// @param properties labels describing the properties of the new block
+///
+/// @returns The status code of this operation. If the status is not
+///          `AML_SUCCESS`, you can use `aml_last_error()` to get the full
+///          error message.
+#[no_mangle]
+#[allow(clippy::cast_possible_truncation)]
+pub unsafe extern fn aml_block_from_array(
+    block: *mut *mut aml_block_t,
+    data: aml_array_t,
+    samples: aml_labels_t,
+    components: *const aml_labels_t,
+    components_count: u64,
+    properties: aml_labels_t,
+) -> aml_status_t {
+    catch_unwind(move || {
+        check_pointers!(block);
+
+        let samples = Labels::try_from(&samples)?;
+        let properties = Labels::try_from(&properties)?;
+
+        let mut components_labels = Vec::new();
+        if components_count != 0 {
+            check_pointers!(components);
+            for component in std::slice::from_raw_parts(components, components_count as usize) {
+                components_labels.push(Labels::try_from(component)?);
+            }
+        }
+
+        let new_block = TensorBlock::new(
+            Box::new(data) as Box<dyn Array>, samples, components_labels, properties,
+        )?;
+        let boxed = Box::new(aml_block_t::new(new_block));
+        *block = Box::into_raw(boxed);
+
+        Ok(())
+    })
+}
+
+
+/// Register a new data origin with the given `name`, and store the
+/// corresponding identifier in `origin`.
+///
+/// Calling this function multiple times with the same `name` will always
+/// return the same `origin`.
+///
+/// @param name name of the data origin as a NULL-terminated UTF-8 string
+/// @param origin pointer to be filled with the data origin identifier
+///
+/// @returns The status code of this operation. If the status is not
+///          `AML_SUCCESS`, you can use `aml_last_error()` to get the full
+///          error message.
+#[no_mangle]
+pub unsafe extern fn aml_register_data_origin(
+    name: *const c_char,
+    origin: *mut aml_data_origin_t,
+) -> aml_status_t {
+    catch_unwind(|| {
+        check_pointers!(name, origin);
+
+        let name = CStr::from_ptr(name).to_str().expect("invalid utf8").to_owned();
+        *origin = crate::array::register_data_origin(name);
+
+        Ok(())
+    })
+}
+
+// These tests build blocks on top of a small custom `aml_array_t` backend
+// (instead of the default `ndarray` one) to exercise the actual C API
+// surface (`aml_register_data_origin`, `aml_block_from_array`) a caller
+// plugs a backend in through, and to check that merging blocks coming
+// from two different backends is rejected instead of panicking. They live
+// here as unit tests rather than in `equistore/tests/` because they need
+// direct access to `aml_array_t`'s fields, which are only visible from
+// inside this crate.
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::ffi::CString;
+
+    use crate::TensorMap;
+
+    use super::*;
+
+    struct VecArrayState {
+        origin: DataOrigin,
+        shape: Vec<u64>,
+        data: Vec<f64>,
+    }
+
+    unsafe extern fn vec_origin(array: *const c_void, origin: *mut aml_data_origin_t) -> aml_status_t {
+        catch_unwind(|| {
+            *origin = (*array.cast::<VecArrayState>()).origin;
+            Ok(())
+        })
+    }
+
+    unsafe extern fn vec_data(array: *mut c_void, data: *mut *mut f64) -> aml_status_t {
+        catch_unwind(|| {
+            *data = (*array.cast::<VecArrayState>()).data.as_mut_ptr();
+            Ok(())
+        })
+    }
+
+    unsafe extern fn vec_shape(array: *const c_void, shape: *mut *const u64, shape_count: *mut u64) -> aml_status_t {
+        catch_unwind(|| {
+            let state = &*array.cast::<VecArrayState>();
+            *shape = state.shape.as_ptr();
+            *shape_count = state.shape.len() as u64;
+            Ok(())
+        })
+    }
+
+    unsafe extern fn vec_create(
+        array: *const c_void,
+        shape: *const u64,
+        shape_count: u64,
+        new_array: *mut aml_array_t,
+    ) -> aml_status_t {
+        catch_unwind(|| {
+            let origin = (*array.cast::<VecArrayState>()).origin;
+            let shape = std::slice::from_raw_parts(shape, shape_count as usize).to_vec();
+            let total = shape.iter().product::<u64>() as usize;
+
+            *new_array = vec_array_t(origin, shape, vec![0.0; total]);
+            Ok(())
+        })
+    }
+
+    unsafe extern fn vec_move_samples_from(
+        array: *mut c_void,
+        other: *const c_void,
+        samples: *const aml_sample_mapping_t,
+        samples_count: u64,
+        properties: *const aml_sample_mapping_t,
+        properties_count: u64,
+    ) -> aml_status_t {
+        catch_unwind(|| {
+            let output = &mut *array.cast::<VecArrayState>();
+            let input = &*other.cast::<VecArrayState>();
+
+            let samples = std::slice::from_raw_parts(samples, samples_count as usize);
+            let properties = std::slice::from_raw_parts(properties, properties_count as usize);
+
+            let output_properties_count = *output.shape.last().expect("shape is never empty") as usize;
+            let input_properties_count = *input.shape.last().expect("shape is never empty") as usize;
+
+            for sample in samples {
+                for property in properties {
+                    let output_index = sample.output as usize * output_properties_count + property.output as usize;
+                    let input_index = sample.input as usize * input_properties_count + property.input as usize;
+                    output.data[output_index] = input.data[input_index];
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    unsafe extern fn vec_destroy(array: *mut c_void) {
+        std::mem::drop(Box::from_raw(array.cast::<VecArrayState>()));
+    }
+
+    /// Build an `aml_array_t` backed by a plain `Vec<f64>`, to stand in for
+    /// a foreign (non-`ndarray`) backend in these tests.
+    fn vec_array_t(origin: DataOrigin, shape: Vec<u64>, data: Vec<f64>) -> aml_array_t {
+        let state = Box::new(VecArrayState { origin, shape, data });
+        aml_array_t {
+            ptr: Box::into_raw(state).cast(),
+            origin: Some(vec_origin),
+            data: Some(vec_data),
+            shape: Some(vec_shape),
+            reshape: None,
+            swap_axes: None,
+            create: Some(vec_create),
+            copy: None,
+            move_samples_from: Some(vec_move_samples_from),
+            destroy: Some(vec_destroy),
+        }
+    }
+
+    fn register_vec_origin(name: &str) -> DataOrigin {
+        let name = CString::new(name).unwrap();
+        let mut origin = 0;
+        let status = unsafe { aml_register_data_origin(name.as_ptr(), &mut origin) };
+        assert!(status.is_success());
+        origin
+    }
+
+    #[test]
+    fn block_from_custom_backend_round_trips() {
+        let origin = register_vec_origin("test.vec_backend.round_trip");
+
+        let samples = Labels::new(&["sample"], &[[0.into()], [1.into()]]).unwrap();
+        let properties = Labels::new(&["property"], &[[0.into()]]).unwrap();
+
+        let array = vec_array_t(origin, vec![2, 1], vec![1.0, 2.0]);
+        let samples_ffi: aml_labels_t = (&samples).try_into().unwrap();
+        let properties_ffi: aml_labels_t = (&properties).try_into().unwrap();
+
+        let mut block = std::ptr::null_mut();
+        let status = unsafe {
+            aml_block_from_array(&mut block, array, samples_ffi, std::ptr::null(), 0, properties_ffi)
+        };
+        assert!(status.is_success());
+
+        let block = unsafe { Box::from_raw(block) };
+        assert_eq!(block.values().as_array().as_slice().unwrap(), [1.0, 2.0]);
+    }
+
+    #[test]
+    fn merging_same_backend_blocks_uses_move_samples_from() {
+        let origin = register_vec_origin("test.vec_backend.same_backend_merge");
+
+        let samples = Labels::new(&["sample"], &[[0.into()]]).unwrap();
+        let properties = Labels::new(&["property"], &[[0.into()]]).unwrap();
+
+        let first = TensorBlock::new(
+            Box::new(vec_array_t(origin, vec![1, 1], vec![1.0])) as Box<dyn Array>,
+            samples.clone(), Vec::new(), properties.clone(),
+        ).unwrap();
+        let second = TensorBlock::new(
+            Box::new(vec_array_t(origin, vec![1, 1], vec![2.0])) as Box<dyn Array>,
+            samples, Vec::new(), properties,
+        ).unwrap();
+
+        let keys = Labels::new(
+            &["group", "backend"], &[[0.into(), 0.into()], [0.into(), 1.into()]],
+        ).unwrap();
+        let mut tensor = TensorMap::new(keys, vec![first, second]).unwrap();
+
+        tensor.keys_to_samples(&["backend"]).unwrap();
+
+        let merged = tensor.block_by_id(0);
+        assert_eq!(merged.values().as_array().as_slice().unwrap(), [1.0, 2.0]);
+    }
+
+    #[test]
+    fn merging_different_backends_errors_instead_of_panicking() {
+        let origin = register_vec_origin("test.vec_backend.cross_backend_merge");
+
+        let samples = Labels::new(&["sample"], &[[0.into()]]).unwrap();
+        let properties = Labels::new(&["property"], &[[0.into()]]).unwrap();
+
+        let custom_block = TensorBlock::new(
+            Box::new(vec_array_t(origin, vec![1, 1], vec![1.0])) as Box<dyn Array>,
+            samples.clone(), Vec::new(), properties.clone(),
+        ).unwrap();
+        let ndarray_block = TensorBlock::new(
+            ndarray::ArrayD::from_elem(ndarray::IxDyn(&[1, 1]), 2.0),
+            samples, Vec::new(), properties,
+        ).unwrap();
+
+        let keys = Labels::new(
+            &["group", "backend"], &[[0.into(), 0.into()], [0.into(), 1.into()]],
+        ).unwrap();
+        let mut tensor = TensorMap::new(keys, vec![custom_block, ndarray_block]).unwrap();
+
+        // this used to panic inside `ndarray::ArrayD<f64>::move_samples_from`
+        // (a failed `downcast_ref`) instead of surfacing a `Result` error
+        let error = tensor.keys_to_samples(&["backend"]).unwrap_err();
+        assert!(error.to_string().contains("different origins"));
+    }
+}
@@ -0,0 +1,29 @@
+use crate::TensorBlock;
+
+/// Opaque type representing a `TensorBlock`.
+#[allow(non_camel_case_types)]
+pub struct aml_block_t(TensorBlock);
+
+impl std::ops::Deref for aml_block_t {
+    type Target = TensorBlock;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for aml_block_t {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl aml_block_t {
+    pub(crate) fn new(block: TensorBlock) -> Self {
+        aml_block_t(block)
+    }
+
+    /// Take ownership of the `TensorBlock` wrapped by this `aml_block_t`.
+    pub(crate) fn block(self) -> TensorBlock {
+        self.0
+    }
+}
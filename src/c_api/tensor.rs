@@ -1,4 +1,4 @@
-use std::os::raw::c_char;
+use std::os::raw::{c_char, c_void};
 use std::ffi::CStr;
 use std::convert::{TryFrom, TryInto};
 use std::collections::BTreeSet;
@@ -26,6 +26,12 @@ impl std::ops::DerefMut for aml_tensormap_t {
     }
 }
 
+impl aml_tensormap_t {
+    pub(crate) fn new(tensor: TensorMap) -> Self {
+        aml_tensormap_t(tensor)
+    }
+}
+
 
 /// Create a new `aml_tensormap_t` with the given `keys` and `blocks`.
 /// `blocks_count` must be set to the number of entries in the blocks array.
@@ -205,6 +211,65 @@ pub unsafe extern fn aml_tensormap_block_selection(
 }
 
 
+/// Get a list of block indices matching the given `selection`.
+///
+/// The `selection`'s names must be a subset of the names used in the keys
+/// for this tensor map. A block is part of the output if its key agrees
+/// with one of the rows of `selection` for all the variables the selection
+/// defines; every block is returned if `selection` contains no variable.
+///
+/// This function should be called once with `block_indices` set to `NULL`
+/// to get the number of matching blocks in `count`, and then once more
+/// with `block_indices` pointing to an array of at least `count`
+/// pre-allocated `u64` to get the actual block indices, sorted in
+/// increasing order.
+///
+/// @param tensor pointer to an existing tensor map
+/// @param block_indices pointer to pre-allocated memory, or `NULL`
+/// @param count number of entries in `block_indices` on input if it is not
+///              `NULL`; set to the number of matching blocks on output
+/// @param selection labels containing the selection variables and values
+///
+/// @returns The status code of this operation. If the status is not
+///          `AML_SUCCESS`, you can use `aml_last_error()` to get the full
+///          error message.
+#[no_mangle]
+#[allow(clippy::cast_possible_truncation)]
+pub unsafe extern fn aml_tensormap_blocks_matching(
+    tensor: *const aml_tensormap_t,
+    block_indices: *mut u64,
+    count: *mut u64,
+    selection: aml_labels_t,
+) -> aml_status_t {
+    catch_unwind(|| {
+        check_pointers!(tensor, count);
+
+        let selection = Labels::try_from(&selection)?;
+        let matching = (*tensor).blocks_matching(&selection)?;
+
+        if block_indices.is_null() {
+            *count = matching.len() as u64;
+        } else {
+            if (*count as usize) < matching.len() {
+                return Err(Error::InvalidParameter(format!(
+                    "not enough space in 'block_indices': need at least {} entries, got {}",
+                    matching.len(), *count
+                )));
+            }
+
+            let output = std::slice::from_raw_parts_mut(block_indices, matching.len());
+            for (position, block_i) in matching.into_iter().enumerate() {
+                output[position] = block_i as u64;
+            }
+
+            *count = matching.len() as u64;
+        }
+
+        Ok(())
+    })
+}
+
+
 /// Move the given `variables` from the keys to the property labels of the
 /// blocks.
 ///
@@ -214,6 +279,11 @@ pub unsafe extern fn aml_tensormap_block_selection(
 /// new sample labels will contains all of the merged blocks sample labels,
 /// re-ordered to keep them lexicographically sorted.
 ///
+/// Blocks being merged together do not need to have the same sample labels:
+/// the merged block uses the union of the sample labels of the merged
+/// blocks, and the values (and gradients) of a sample a given block does
+/// not define are set to zero.
+///
 /// `variables` must be an array of `variables_count` NULL-terminated strings,
 /// encoded as UTF-8.
 ///
@@ -291,8 +361,10 @@ pub unsafe extern fn aml_tensormap_components_to_properties(
 /// be merged together. The resulting merged blocks will have `variables` as
 /// the last sample variables, preceded by the current samples.
 ///
-/// This function is only implemented if all merged block have the same
-/// property labels.
+/// Blocks being merged together do not need to have the same property
+/// labels: the merged block uses the union of the property labels of the
+/// merged blocks, and the values (and gradients) of a property a given
+/// block does not define are set to zero.
 ///
 /// `variables` must be an array of `variables_count` NULL-terminated strings,
 /// encoded as UTF-8.
@@ -323,6 +395,165 @@ pub unsafe extern fn aml_tensormap_keys_to_samples(
 
         (*tensor).keys_to_samples(&rust_variables)?;
 
+        Ok(())
+    })
+}
+
+
+/// Function pointer used by `aml_tensormap_save_buffer` to grow the output
+/// buffer as needed.
+///
+/// This function should behave like `realloc`: `user_data` is the opaque
+/// pointer given to `aml_tensormap_save_buffer`; `ptr` is `NULL` on the
+/// first call and the previously returned pointer on subsequent calls;
+/// `new_size` is the total size in bytes the buffer must be grown to. The
+/// callback should return a pointer to a buffer of at least `new_size`
+/// bytes that keeps the contents of the previous allocation, or `NULL` to
+/// signal an allocation failure.
+#[allow(non_camel_case_types)]
+pub type aml_realloc_buffer_t = Option<unsafe extern fn(
+    user_data: *mut c_void,
+    ptr: *mut u8,
+    new_size: u64,
+) -> *mut u8>;
+
+
+/// Load a tensor map from the file at the given `path`.
+///
+/// The file format used is documented in the `equistore::io` module of the
+/// Rust core, and is based on the NPZ format (a zip archive of NPY files)
+/// already used by `numpy`.
+///
+/// @param path path to the file to load, encoded as UTF-8
+/// @param tensor pointer to be filled with a pointer to the new tensor map
+///
+/// @returns The status code of this operation. If the status is not
+///          `AML_SUCCESS`, you can use `aml_last_error()` to get the full
+///          error message.
+#[no_mangle]
+pub unsafe extern fn aml_tensormap_load(
+    path: *const c_char,
+    tensor: *mut *mut aml_tensormap_t,
+) -> aml_status_t {
+    catch_unwind(|| {
+        check_pointers!(path, tensor);
+
+        let path = CStr::from_ptr(path).to_str().expect("invalid utf8");
+        let loaded = equistore::io::load(path)?;
+
+        let boxed = Box::new(aml_tensormap_t::new(loaded));
+        *tensor = Box::into_raw(boxed);
+
+        Ok(())
+    })
+}
+
+
+/// Load a tensor map from an in-memory buffer.
+///
+/// @param buffer pointer to the first byte of the buffer to load
+/// @param buffer_count number of bytes in `buffer`
+/// @param tensor pointer to be filled with a pointer to the new tensor map
+///
+/// @returns The status code of this operation. If the status is not
+///          `AML_SUCCESS`, you can use `aml_last_error()` to get the full
+///          error message.
+#[no_mangle]
+#[allow(clippy::cast_possible_truncation)]
+pub unsafe extern fn aml_tensormap_load_buffer(
+    buffer: *const u8,
+    buffer_count: u64,
+    tensor: *mut *mut aml_tensormap_t,
+) -> aml_status_t {
+    catch_unwind(|| {
+        check_pointers!(buffer, tensor);
+
+        let slice = std::slice::from_raw_parts(buffer, buffer_count as usize);
+        let loaded = equistore::io::load_buffer(slice)?;
+
+        let boxed = Box::new(aml_tensormap_t::new(loaded));
+        *tensor = Box::into_raw(boxed);
+
+        Ok(())
+    })
+}
+
+
+/// Save a tensor map to the file at the given `path`.
+///
+/// If the file already exists, it will be overwritten.
+///
+/// @param path path to the file to save, encoded as UTF-8
+/// @param tensor pointer to an existing tensor map
+///
+/// @returns The status code of this operation. If the status is not
+///          `AML_SUCCESS`, you can use `aml_last_error()` to get the full
+///          error message.
+#[no_mangle]
+pub unsafe extern fn aml_tensormap_save(
+    path: *const c_char,
+    tensor: *const aml_tensormap_t,
+) -> aml_status_t {
+    catch_unwind(|| {
+        check_pointers!(path, tensor);
+
+        let path = CStr::from_ptr(path).to_str().expect("invalid utf8");
+        equistore::io::save(path, &*tensor)?;
+
+        Ok(())
+    })
+}
+
+
+/// Save a tensor map to an in-memory buffer, growing it as required.
+///
+/// Since the size of the serialized data is not known ahead of time, the
+/// buffer is allocated and grown by the caller through the `realloc`
+/// callback, which behaves like the standard `realloc` function, except it
+/// also receives the opaque `user_data` pointer given to this function.
+///
+/// @param realloc callback used to allocate and grow the output buffer
+/// @param user_data opaque pointer passed unchanged to `realloc`
+/// @param buffer pointer to be filled with the pointer returned by the last
+///               call to `realloc`
+/// @param buffer_count pointer to be filled with the number of bytes
+///                      written to `buffer`
+/// @param tensor pointer to an existing tensor map
+///
+/// @returns The status code of this operation. If the status is not
+///          `AML_SUCCESS`, you can use `aml_last_error()` to get the full
+///          error message.
+#[no_mangle]
+#[allow(clippy::cast_possible_truncation)]
+pub unsafe extern fn aml_tensormap_save_buffer(
+    realloc: aml_realloc_buffer_t,
+    user_data: *mut c_void,
+    buffer: *mut *mut u8,
+    buffer_count: *mut u64,
+    tensor: *const aml_tensormap_t,
+) -> aml_status_t {
+    catch_unwind(|| {
+        check_pointers!(tensor, buffer, buffer_count);
+
+        let realloc = realloc.ok_or_else(|| Error::InvalidParameter(
+            "got a NULL realloc callback in aml_tensormap_save_buffer".into()
+        ))?;
+
+        let mut serialized = Vec::new();
+        equistore::io::save_buffer(&*tensor, &mut serialized)?;
+
+        let new_buffer = realloc(user_data, std::ptr::null_mut(), serialized.len() as u64);
+        if new_buffer.is_null() {
+            return Err(Error::InvalidParameter(
+                "realloc callback failed to allocate memory in aml_tensormap_save_buffer".into()
+            ));
+        }
+
+        std::ptr::copy_nonoverlapping(serialized.as_ptr(), new_buffer, serialized.len());
+
+        *buffer = new_buffer;
+        *buffer_count = serialized.len() as u64;
+
         Ok(())
     })
 }
\ No newline at end of file
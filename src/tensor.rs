@@ -0,0 +1,447 @@
+use std::collections::BTreeSet;
+
+use crate::array::SampleMapping;
+use crate::{Labels, LabelValue, TensorBlock, TensorMap, Error};
+
+impl TensorMap {
+    /// Get the indices of the blocks whose keys match the given `selection`.
+    ///
+    /// `selection` must use a subset of the names used in the keys of this
+    /// tensor map. A block is part of the output if its key agrees with one
+    /// of the rows of `selection` for all the variables named in
+    /// `selection`; if `selection` contains no variable, every block is
+    /// returned.
+    pub fn blocks_matching(&self, selection: &Labels) -> Result<Vec<usize>, Error> {
+        let keys = self.keys();
+
+        let mut positions = Vec::new();
+        for name in selection.names() {
+            let position = keys.names().iter().position(|&key_name| key_name == name);
+            let position = position.ok_or_else(|| Error::InvalidParameter(format!(
+                "'{}' in the selection is not part of the keys for this tensor map", name
+            )))?;
+            positions.push(position);
+        }
+
+        let mut matching = Vec::new();
+        for (block_i, key) in keys.iter().enumerate() {
+            if positions.is_empty() {
+                matching.push(block_i);
+                continue;
+            }
+
+            let matches = selection.iter().any(|selected| {
+                positions.iter().enumerate().all(|(selection_i, &key_position)| {
+                    key[key_position] == selected[selection_i]
+                })
+            });
+
+            if matches {
+                matching.push(block_i);
+            }
+        }
+
+        Ok(matching)
+    }
+
+    /// Merge blocks that agree on all key variables *except* the given
+    /// `variables`, moving `variables` to the end of the samples of the
+    /// merged block.
+    ///
+    /// Merged blocks are no longer required to share the same property
+    /// labels, nor to all define the same gradients: the merged block uses
+    /// the sorted union of the property labels of the blocks being merged,
+    /// and the union of their gradients, zero-filling the values of a
+    /// property or gradient sample a given block does not define. This
+    /// mirrors how [`TensorMap::keys_to_properties`] already handles blocks
+    /// with different sample labels.
+    pub fn keys_to_samples(&mut self, variables: &[&str]) -> Result<(), Error> {
+        let keys = self.keys().clone();
+        let moved_positions = moved_key_positions(&keys, variables)?;
+        let (remaining_names, groups) = group_by_remaining_keys(&keys, &moved_positions);
+
+        let mut new_keys_values = Vec::new();
+        let mut new_blocks = Vec::new();
+        for (group_key, block_indices) in groups {
+            new_keys_values.push(group_key);
+            new_blocks.push(merge_keys_to_samples(self, &keys, &block_indices, variables)?);
+        }
+
+        let new_keys = Labels::new(&remaining_names, &new_keys_values)?;
+        *self = TensorMap::new(new_keys, new_blocks)?;
+
+        Ok(())
+    }
+
+    /// Merge blocks that agree on all key variables *except* the given
+    /// `variables`, moving `variables` to the front of the properties of
+    /// the merged block.
+    ///
+    /// Unlike [`TensorMap::keys_to_samples`], the property labels of the
+    /// merged blocks are not required to overlap at all: each block
+    /// contributes its own, distinct range of columns to the merged
+    /// properties (prefixed with the moved key values, which is what keeps
+    /// otherwise-identical property rows from different blocks distinct).
+    /// Blocks being merged together do not need to have the same sample
+    /// labels either: the merged block uses the sorted union of the sample
+    /// labels of the blocks being merged, zero-filling the values (and
+    /// gradients) of a sample a given block does not define.
+    pub fn keys_to_properties(&mut self, variables: &[&str]) -> Result<(), Error> {
+        let keys = self.keys().clone();
+        let moved_positions = moved_key_positions(&keys, variables)?;
+        let (remaining_names, groups) = group_by_remaining_keys(&keys, &moved_positions);
+
+        let mut new_keys_values = Vec::new();
+        let mut new_blocks = Vec::new();
+        for (group_key, block_indices) in groups {
+            new_keys_values.push(group_key);
+            new_blocks.push(merge_keys_to_properties(self, &keys, &block_indices, variables)?);
+        }
+
+        let new_keys = Labels::new(&remaining_names, &new_keys_values)?;
+        *self = TensorMap::new(new_keys, new_blocks)?;
+
+        Ok(())
+    }
+}
+
+/// Find the position of each of `variables` in `keys`' names, erroring out if
+/// one of them is not part of the keys.
+fn moved_key_positions(keys: &Labels, variables: &[&str]) -> Result<Vec<usize>, Error> {
+    let mut positions = Vec::new();
+    for &variable in variables {
+        let position = keys.names().iter().position(|&name| name == variable);
+        let position = position.ok_or_else(|| Error::InvalidParameter(format!(
+            "'{}' is not part of the keys for this tensor map", variable
+        )))?;
+        positions.push(position);
+    }
+
+    Ok(positions)
+}
+
+/// Group the blocks of `keys` by the values of the key variables that are
+/// *not* at `moved_positions`; blocks in the same group are meant to be
+/// merged together. Returns the names of the remaining (not moved) key
+/// variables, together with the groups themselves.
+fn group_by_remaining_keys<'a>(
+    keys: &'a Labels,
+    moved_positions: &[usize],
+) -> (Vec<&'a str>, Vec<(Vec<LabelValue>, Vec<usize>)>) {
+    let mut groups: Vec<(Vec<LabelValue>, Vec<usize>)> = Vec::new();
+    for (block_i, key) in keys.iter().enumerate() {
+        let remaining: Vec<_> = key.iter().enumerate()
+            .filter(|&(position, _)| !moved_positions.contains(&position))
+            .map(|(_, &value)| value)
+            .collect();
+
+        match groups.iter_mut().find(|(group_key, _)| group_key == &remaining) {
+            Some((_, block_indices)) => block_indices.push(block_i),
+            None => groups.push((remaining, vec![block_i])),
+        }
+    }
+
+    let remaining_names: Vec<&str> = keys.names().iter().copied().enumerate()
+        .filter(|&(position, _)| !moved_positions.contains(&position))
+        .map(|(_, name)| name)
+        .collect();
+
+    (remaining_names, groups)
+}
+
+/// Merge the blocks at `block_indices` together, moving `variables` from
+/// the keys to the end of the samples, and taking the union of the
+/// property labels of the merged blocks.
+fn merge_keys_to_samples(
+    tensor: &TensorMap,
+    keys: &Labels,
+    block_indices: &[usize],
+    variables: &[&str],
+) -> Result<TensorBlock, Error> {
+    let blocks: Vec<&TensorBlock> = block_indices.iter().map(|&i| &tensor.blocks()[i]).collect();
+    let reference = blocks[0];
+
+    // sorted union of the property labels across all the blocks being merged
+    let mut properties_union = BTreeSet::new();
+    for block in &blocks {
+        for property in block.properties().iter() {
+            properties_union.insert(property.to_vec());
+        }
+    }
+    let properties_values: Vec<_> = properties_union.into_iter().collect();
+    let merged_properties = Labels::new(reference.properties().names(), &properties_values)?;
+
+    // for each merged block, the column in `merged_properties` matching
+    // each of its own properties, in order
+    let property_mappings: Vec<Vec<usize>> = blocks.iter().map(|block| {
+        block.properties().iter().map(|property| {
+            merged_properties.iter().position(|row| row == property)
+                .expect("property from a merged block is always part of the union")
+        }).collect()
+    }).collect();
+
+    // union of the samples, with the moved key `variables` appended
+    let mut sample_names: Vec<&str> = reference.samples().names().to_vec();
+    sample_names.extend_from_slice(variables);
+
+    let mut merged_samples = BTreeSet::new();
+    let mut extra_values_by_block = Vec::new();
+    for (&block_i, block) in block_indices.iter().zip(&blocks) {
+        let key = keys.iter().nth(block_i).expect("valid block index");
+        let extra_values: Vec<LabelValue> = variables.iter().map(|&variable| {
+            let position = keys.names().iter().position(|&name| name == variable)
+                .expect("variable was already validated to be part of the keys");
+            key[position]
+        }).collect();
+
+        for sample in block.samples().iter() {
+            let mut full_sample = sample.to_vec();
+            full_sample.extend_from_slice(&extra_values);
+            merged_samples.insert(full_sample);
+        }
+
+        extra_values_by_block.push(extra_values);
+    }
+    let merged_samples_values: Vec<_> = merged_samples.into_iter().collect();
+    let merged_samples = Labels::new(&sample_names, &merged_samples_values)?;
+
+    let mut values = reference.values().create(&{
+        let mut shape = reference.values().as_array().shape().to_vec();
+        shape[0] = merged_samples.count();
+        shape[shape.len() - 1] = merged_properties.count();
+        shape
+    })?;
+
+    for ((block, property_mapping), extra_values) in blocks.iter().zip(&property_mappings).zip(&extra_values_by_block) {
+        let properties_mapping: Vec<SampleMapping> = property_mapping.iter().enumerate()
+            .map(|(input, &output)| SampleMapping { input, output })
+            .collect();
+
+        let samples_mapping: Vec<SampleMapping> = block.samples().iter().enumerate().map(|(input, sample)| {
+            let mut full_sample = sample.to_vec();
+            full_sample.extend_from_slice(extra_values);
+
+            let output = merged_samples.position(&full_sample)
+                .expect("merged sample is always part of the output samples");
+
+            SampleMapping { input, output }
+        }).collect();
+
+        values.move_samples_from(block.values(), &samples_mapping, &properties_mapping)?;
+    }
+
+    let mut merged_block = TensorBlock::new(
+        values, merged_samples, reference.components().to_vec(), merged_properties,
+    )?;
+
+    // union of the gradient names defined across *all* merged blocks: a
+    // block might not define a gradient another block in the same group
+    // does, in which case it simply does not contribute any sample to it
+    let mut gradient_names = BTreeSet::new();
+    for block in &blocks {
+        gradient_names.extend(block.gradient_list());
+    }
+
+    for gradient_name in gradient_names {
+        let defining_blocks: Vec<&TensorBlock> = blocks.iter()
+            .filter(|block| block.gradient(gradient_name).is_some())
+            .copied()
+            .collect();
+
+        let merged_gradient_samples_names: Vec<&str> = defining_blocks[0].gradient(gradient_name)
+            .expect("just checked this block defines the gradient")
+            .samples().names().to_vec();
+
+        let mut merged_gradient_samples = BTreeSet::new();
+        for block in &defining_blocks {
+            let gradient = block.gradient(gradient_name).expect("just checked this block defines the gradient");
+            for sample in gradient.samples().iter() {
+                merged_gradient_samples.insert(sample.to_vec());
+            }
+        }
+        let merged_gradient_samples_values: Vec<_> = merged_gradient_samples.into_iter().collect();
+        let merged_gradient_samples = Labels::new(&merged_gradient_samples_names, &merged_gradient_samples_values)?;
+
+        let reference_gradient = defining_blocks[0].gradient(gradient_name).expect("checked above");
+        let mut gradient_values = reference_gradient.values().create(&{
+            let mut shape = reference_gradient.values().as_array().shape().to_vec();
+            shape[0] = merged_gradient_samples.count();
+            shape[shape.len() - 1] = merged_properties.count();
+            shape
+        })?;
+
+        for (block, property_mapping) in blocks.iter().zip(&property_mappings) {
+            let gradient = match block.gradient(gradient_name) {
+                Some(gradient) => gradient,
+                // this block does not define this gradient: leave the
+                // corresponding output samples zero-filled
+                None => continue,
+            };
+
+            let properties_mapping: Vec<SampleMapping> = property_mapping.iter().enumerate()
+                .map(|(input, &output)| SampleMapping { input, output })
+                .collect();
+
+            let samples_mapping: Vec<SampleMapping> = gradient.samples().iter().enumerate().map(|(input, sample)| {
+                let output = merged_gradient_samples.position(sample)
+                    .expect("merged gradient sample is always part of the output samples");
+                SampleMapping { input, output }
+            }).collect();
+
+            gradient_values.move_samples_from(gradient.values(), &samples_mapping, &properties_mapping)?;
+        }
+
+        merged_block.add_gradient(
+            gradient_name, gradient_values, merged_gradient_samples, reference_gradient.components().to_vec(),
+        )?;
+    }
+
+    Ok(merged_block)
+}
+
+/// Merge the blocks at `block_indices` together, moving `variables` from
+/// the keys to the front of the properties, and taking the union of the
+/// sample labels of the merged blocks.
+///
+/// Unlike [`merge_keys_to_samples`], the merged properties are not
+/// deduplicated across blocks: each block keeps its own property rows,
+/// prefixed with the moved key values, and contributes a distinct range of
+/// columns to the merged block.
+fn merge_keys_to_properties(
+    tensor: &TensorMap,
+    keys: &Labels,
+    block_indices: &[usize],
+    variables: &[&str],
+) -> Result<TensorBlock, Error> {
+    let blocks: Vec<&TensorBlock> = block_indices.iter().map(|&i| &tensor.blocks()[i]).collect();
+    let reference = blocks[0];
+
+    let mut property_names: Vec<&str> = variables.to_vec();
+    property_names.extend_from_slice(reference.properties().names());
+
+    // concatenate each block's own properties, prefixed with the values of
+    // the moved key `variables` for that block; this is what keeps two
+    // blocks' properties from colliding even if their non-key columns are
+    // otherwise identical, so no deduplication is needed here (unlike the
+    // property union in `merge_keys_to_samples`)
+    let mut merged_properties_values = Vec::new();
+    // for each merged block, the offset of its own properties in the
+    // merged properties
+    let mut property_offsets = Vec::new();
+    for (&block_i, block) in block_indices.iter().zip(&blocks) {
+        let key = keys.iter().nth(block_i).expect("valid block index");
+        let extra_values: Vec<LabelValue> = variables.iter().map(|&variable| {
+            let position = keys.names().iter().position(|&name| name == variable)
+                .expect("variable was already validated to be part of the keys");
+            key[position]
+        }).collect();
+
+        property_offsets.push(merged_properties_values.len());
+        for property in block.properties().iter() {
+            let mut full_property = extra_values.clone();
+            full_property.extend_from_slice(property);
+            merged_properties_values.push(full_property);
+        }
+    }
+    let merged_properties = Labels::new(&property_names, &merged_properties_values)?;
+
+    // sorted union of the samples across all the blocks being merged
+    let mut samples_union = BTreeSet::new();
+    for block in &blocks {
+        for sample in block.samples().iter() {
+            samples_union.insert(sample.to_vec());
+        }
+    }
+    let merged_samples_values: Vec<_> = samples_union.into_iter().collect();
+    let merged_samples = Labels::new(reference.samples().names(), &merged_samples_values)?;
+
+    let mut values = reference.values().create(&{
+        let mut shape = reference.values().as_array().shape().to_vec();
+        shape[0] = merged_samples.count();
+        shape[shape.len() - 1] = merged_properties.count();
+        shape
+    })?;
+
+    for (block, &offset) in blocks.iter().zip(&property_offsets) {
+        let properties_mapping: Vec<SampleMapping> = (0..block.properties().count())
+            .map(|input| SampleMapping { input, output: offset + input })
+            .collect();
+
+        let samples_mapping: Vec<SampleMapping> = block.samples().iter().enumerate().map(|(input, sample)| {
+            let output = merged_samples.position(sample)
+                .expect("merged sample is always part of the output samples");
+            SampleMapping { input, output }
+        }).collect();
+
+        values.move_samples_from(block.values(), &samples_mapping, &properties_mapping)?;
+    }
+
+    let mut merged_block = TensorBlock::new(
+        values, merged_samples, reference.components().to_vec(), merged_properties,
+    )?;
+
+    // union of the gradient names defined across *all* merged blocks, same
+    // as in `merge_keys_to_samples`
+    let mut gradient_names = BTreeSet::new();
+    for block in &blocks {
+        gradient_names.extend(block.gradient_list());
+    }
+
+    for gradient_name in gradient_names {
+        let defining_blocks: Vec<&TensorBlock> = blocks.iter()
+            .filter(|block| block.gradient(gradient_name).is_some())
+            .copied()
+            .collect();
+
+        let reference_gradient = defining_blocks[0].gradient(gradient_name)
+            .expect("just checked this block defines the gradient");
+
+        // sorted union of the gradient samples across the blocks that
+        // define this gradient
+        let mut gradient_samples_union = BTreeSet::new();
+        for block in &defining_blocks {
+            let gradient = block.gradient(gradient_name).expect("just checked this block defines the gradient");
+            for sample in gradient.samples().iter() {
+                gradient_samples_union.insert(sample.to_vec());
+            }
+        }
+        let merged_gradient_samples_values: Vec<_> = gradient_samples_union.into_iter().collect();
+        let merged_gradient_samples = Labels::new(
+            reference_gradient.samples().names(), &merged_gradient_samples_values,
+        )?;
+
+        let mut gradient_values = reference_gradient.values().create(&{
+            let mut shape = reference_gradient.values().as_array().shape().to_vec();
+            shape[0] = merged_gradient_samples.count();
+            shape[shape.len() - 1] = merged_properties.count();
+            shape
+        })?;
+
+        for (block, &offset) in blocks.iter().zip(&property_offsets) {
+            let gradient = match block.gradient(gradient_name) {
+                Some(gradient) => gradient,
+                // this block does not define this gradient: leave the
+                // corresponding output samples zero-filled
+                None => continue,
+            };
+
+            let properties_mapping: Vec<SampleMapping> = (0..block.properties().count())
+                .map(|input| SampleMapping { input, output: offset + input })
+                .collect();
+
+            let samples_mapping: Vec<SampleMapping> = gradient.samples().iter().enumerate().map(|(input, sample)| {
+                let output = merged_gradient_samples.position(sample)
+                    .expect("merged gradient sample is always part of the output samples");
+                SampleMapping { input, output }
+            }).collect();
+
+            gradient_values.move_samples_from(gradient.values(), &samples_mapping, &properties_mapping)?;
+        }
+
+        merged_block.add_gradient(
+            gradient_name, gradient_values, merged_gradient_samples, reference_gradient.components().to_vec(),
+        )?;
+    }
+
+    Ok(merged_block)
+}